@@ -63,12 +63,65 @@ mod app {
     use rp_pico::hal::{self, Sio};
 
     // Import pio crates
-    use hal::pio::{PIOBuilder, Tx};
+    use hal::pio::{PIOBuilder, Rx, ShiftDirection, Tx};
     use pio_proc::pio_file;
 
+    // Import the DMA abstraction used to stream the step table to the PIO
+    // TX FIFO without CPU involvement.
+    use cortex_m::singleton;
+    use hal::dma::{double_buffer, DMAExt};
+
     // Pull in any important traits
     use rp_pico::hal::prelude::*;
 
+    // 7-segment font table, hex digits 0-F, in the exact A-551SR pin
+    // order from the diagram at the top of this file: bit 0 is pin 1
+    // (segment "2"'s opposite side, i.e. "e"), bit 1 is pin 2 ("d"),
+    // bit 2 is pin 4 ("c"), bit 3 is pin 6 ("b"), bit 4 is pin 7 ("a"),
+    // bit 5 is pin 9 ("f"), bit 6 is pin 10 ("g"). Pin 5 (the decimal
+    // point) isn't part of this table -- see `show_digit`'s `dp` flag.
+    pub const SEGMENTS: [u8; 16] = [
+        0x3F, // 0
+        0x0C, // 1
+        0x5B, // 2
+        0x5E, // 3
+        0x6C, // 4
+        0x76, // 5
+        0x77, // 6
+        0x1C, // 7
+        0x7F, // 8
+        0x7E, // 9
+        0x7D, // A
+        0x67, // b
+        0x33, // C
+        0x4F, // d
+        0x73, // E
+        0x71, // F
+    ];
+
+    // Maps each bit of a `SEGMENTS` mask (e, d, c, b, a, f, g) to its
+    // index within one digit's slice of `PwmData::pwm_levels`; pin 5 (the
+    // decimal point) lives at offset 3 and is handled separately from the
+    // 7-segment mask.
+    const SEGMENT_BIT_TO_LEVEL_OFFSET: [usize; 7] = [0, 1, 2, 4, 5, 6, 7];
+    const DP_LEVEL_OFFSET: usize = 3;
+
+    // Detented encoders emit 4 edges (one full jump-table cycle) per
+    // physical click, so divide the raw count down before using it.
+    const ENCODER_EDGES_PER_CLICK: i32 = 4;
+
+    // DHT22/DHT11 only sample reliably at up to 0.5 Hz.
+    const DHT_SAMPLE_INTERVAL_MS: u64 = 2000;
+
+    // Number of cascaded 74HC595s (one per displayed digit). The whole
+    // chain's segment data has to fit in a single 32-bit word (see
+    // `packed_steps`), so this can be at most 4. `shift_register.pio`'s
+    // `bit_count` define has to match `LEVELS - 1`; this is checked
+    // against the program's public defines at boot in `init` rather than
+    // relying on a comment to keep the two in sync.
+    const DIGITS: usize = 4;
+    const LEVELS: usize = DIGITS * 8;
+
     #[derive(Debug, Clone, Copy)]
     struct PwmStep {
         length: u32,
@@ -76,8 +129,8 @@ mod app {
     }
 
     pub struct PwmData {
-        pwm_levels: [u32; 8],
-        pwm_steps: [PwmStep; 9],
+        pwm_levels: [u32; LEVELS],
+        pwm_steps: [PwmStep; LEVELS + 1],
     }
 
     impl PwmData {
@@ -88,16 +141,22 @@ mod app {
             };
 
             Self {
-                pwm_levels: [0; 8],
-                pwm_steps: [null_step; 9],
+                pwm_levels: [0; LEVELS],
+                pwm_steps: [null_step; LEVELS + 1],
             }
         }
 
         fn reflect(&mut self) {
-            let mut indices: [usize; 8] = [0, 1, 2, 3, 4, 5, 6, 7];
+            let mut indices: [usize; LEVELS] = [0; LEVELS];
+            for (i, index) in indices.iter_mut().enumerate() {
+                *index = i;
+            }
             indices.sort_unstable_by_key(|&i| self.pwm_levels[i]);
 
-            let mut data = 255;
+            // `LEVELS` can be 32 (at the `DIGITS = 4` maximum), and `1u32
+            // << 32` is a shift-by-bit-width -- go through `u64` so the
+            // shift itself can't overflow before truncating back down.
+            let mut data: u32 = ((1u64 << LEVELS) - 1) as u32;
             let mut prev_level = 0;
             let mut cur_level = 0;
 
@@ -116,22 +175,87 @@ mod app {
             }
 
             // period after all pins are set low
-            self.pwm_steps[8] = PwmStep {
+            self.pwm_steps[LEVELS] = PwmStep {
                 length: 255 - cur_level,
                 data: 0,
             };
         }
+
+        // Packs the step table into the word pairs `shift_register.pio`
+        // expects: the full `LEVELS`-bit chain, then the hold length
+        // top-aligned in the following word (shift direction is left, so
+        // `out x, 8` on a freshly-pulled word reads bits 31:24). Two
+        // words per step because the chain alone can fill all 32 bits
+        // once there's more than one register, unlike the single-register
+        // version which packed both fields into one word.
+        fn packed_steps(&self) -> [u32; (LEVELS + 1) * 2] {
+            let mut packed = [0; (LEVELS + 1) * 2];
+            for (i, step) in self.pwm_steps.iter().enumerate() {
+                packed[i * 2] = step.data;
+                packed[i * 2 + 1] = (step.length & 0xff) << 24;
+            }
+            packed
+        }
+
+        // Shows a hex digit (0x0-0xF) on `digit` (0 is the first register
+        // in the chain) via the `SEGMENTS` font table, with every lit
+        // segment driven at `brightness` and every unlit one left dark.
+        pub fn show_digit(&mut self, digit: usize, value: u8, brightness: u32, dp: bool) {
+            self.show_raw(digit, SEGMENTS[(value & 0xf) as usize], brightness, dp);
+        }
+
+        // Shows a raw 7-segment mask (same bit order as `SEGMENTS`) on
+        // `digit` at `brightness`, independent of the font table -- for
+        // callers that already have their own segment pattern. Other
+        // digits are left untouched.
+        pub fn show_raw(&mut self, digit: usize, mask: u8, brightness: u32, dp: bool) {
+            let base = digit * 8;
+
+            for i in 0..8 {
+                self.pwm_levels[base + i] = 0;
+            }
+            for (bit, &offset) in SEGMENT_BIT_TO_LEVEL_OFFSET.iter().enumerate() {
+                if mask & (1 << bit) != 0 {
+                    self.pwm_levels[base + offset] = brightness;
+                }
+            }
+            if dp {
+                self.pwm_levels[base + DP_LEVEL_OFFSET] = brightness;
+            }
+
+            self.reflect();
+        }
     }
 
+    // The pair of DMA channels ping-ponging the packed step table into
+    // PIO0 SM0's TX FIFO. The SM paces each transfer itself via its DREQ,
+    // so `repeat_pwm` only ever has to refill whichever buffer just went
+    // idle.
+    type PwmTransfer = double_buffer::Transfer<
+        hal::dma::CH0,
+        hal::dma::CH1,
+        &'static mut [u32; (LEVELS + 1) * 2],
+        Tx<rp_pico::hal::pio::PIO0SM0>,
+    >;
+
     #[shared]
     struct Shared {
         data: PwmData,
+        // Current sweep position, in the same units as `update_data`'s old
+        // `cur_pos`. Driven by `read_encoder` instead of auto-incrementing.
+        cur_pos: f32,
     }
 
     #[local]
     struct Local {
-        // tx ix is used in only one task, so this can be Local
-        tx: Tx<rp_pico::hal::pio::PIO0SM0>,
+        // transfer is used in only one task, so this can be Local. It's
+        // wrapped in an `Option` so `repeat_pwm` can move it out across
+        // the `wait()`/`read_next()` state change each time round.
+        transfer: Option<PwmTransfer>,
+        // rx is used in only one task, so this can be Local
+        rx: Rx<rp_pico::hal::pio::PIO0SM1>,
+        // dht_rx is used in only one task, so this can be Local
+        dht_rx: Rx<rp_pico::hal::pio::PIO0SM2>,
     }
 
     #[init]
@@ -169,10 +293,15 @@ mod app {
         );
 
         // Note: while the compiler never complains, we cannot use pac::Peripherals::take().unwrap() directly
-        let (mut pio0, sm0, _, _, _) = c.device.PIO0.split(&mut resets);
+        let (mut pio0, sm0, sm1, sm2, _) = c.device.PIO0.split(&mut resets);
 
         // Create a pio program
         let program = pio_file!("./src/shift_register.pio", select_program("shift_register"),);
+        assert_eq!(
+            program.public_defines.bit_count,
+            LEVELS as i32 - 1,
+            "shift_register.pio's bit_count define is out of sync with DIGITS/LEVELS"
+        );
         let installed = pio0.install(&program.program).unwrap();
 
         let out_pin = pins.gpio2.into_function::<hal::gpio::FunctionPio0>();
@@ -185,6 +314,8 @@ mod app {
         let (mut sm, _, tx) = PIOBuilder::from_program(installed)
             .out_pins(out_pin_id, 1)
             .side_set_pin_base(out_pin_id + 1)
+            .out_shift_direction(ShiftDirection::Left)
+            .autopull(false)
             .build(sm0);
 
         #[rustfmt::skip]
@@ -197,62 +328,210 @@ mod app {
         // Start state machine
         let _sm = sm.start();
 
+        // Install the quadrature decoder on a second PIO0 state machine so
+        // a rotary encoder can drive the sweep position instead of the
+        // hard-coded auto-increment.
+        let encoder_program = pio_file!("./src/quadrature.pio", select_program("quadrature"),);
+        let installed_encoder = pio0.install(&encoder_program.program).unwrap();
+
+        let encoder_a_pin = pins.gpio0.into_function::<hal::gpio::FunctionPio0>();
+        let _encoder_b_pin = pins.gpio1.into_function::<hal::gpio::FunctionPio0>();
+
+        let encoder_a_pin_id = encoder_a_pin.id().num;
+        let (mut encoder_sm, rx, _) = PIOBuilder::from_program(installed_encoder)
+            .in_pin_base(encoder_a_pin_id)
+            .in_shift_direction(ShiftDirection::Left) // `in` builds the jump-table index from the low bits
+            .build(sm1);
+
+        #[rustfmt::skip]
+        encoder_sm.set_pindirs([
+            (encoder_a_pin_id,     hal::pio::PinDir::Input),
+            (encoder_a_pin_id + 1, hal::pio::PinDir::Input),
+        ]);
+
+        let _encoder_sm = encoder_sm.start();
+
+        // Install the DHT acquisition program on a third PIO0 state
+        // machine so a temperature/humidity sensor can feed the display.
+        let dht_program = pio_file!("./src/dht.pio", select_program("dht"),);
+        let installed_dht = pio0.install(&dht_program.program).unwrap();
+
+        let dht_pin = pins.gpio5.into_function::<hal::gpio::FunctionPio0>();
+        let dht_pin_id = dht_pin.id().num;
+
+        let (mut dht_sm, dht_rx, _) = PIOBuilder::from_program(installed_dht)
+            .set_pins(dht_pin_id, 1)
+            .in_pin_base(dht_pin_id)
+            .jmp_pin(dht_pin_id)
+            .clock_divisor_fixed_point(125, 0) // 1 us per instruction at 125 MHz
+            .in_shift_direction(ShiftDirection::Left) // MSB first, matching `to_be_bytes()` below
+            .push_threshold(32)
+            .autopush(true)
+            .build(sm2);
+
+        dht_sm.set_pindirs([(dht_pin_id, hal::pio::PinDir::Output)]);
+
+        let _dht_sm = dht_sm.start();
+
         let mut data = PwmData::new();
 
-        data.pwm_levels = [0; 8];
+        data.pwm_levels = [0; LEVELS];
         data.reflect();
 
+        // Hand the step table to a pair of DMA channels instead of writing
+        // each step from the CPU: the SM is self-timed (see
+        // `shift_register.pio`), so once the ping-pong transfer is
+        // started it keeps the waveform running entirely on its own,
+        // paced by its DREQ.
+        let dma = c.device.DMA.split(&mut resets);
+        let tx_buf_a = singleton!(: [u32; (LEVELS + 1) * 2] = data.packed_steps()).unwrap();
+        let tx_buf_b = singleton!(: [u32; (LEVELS + 1) * 2] = [0; (LEVELS + 1) * 2]).unwrap();
+        let transfer = double_buffer::Config::new((dma.ch0, dma.ch1), tx_buf_a, tx)
+            .start()
+            .read_next(tx_buf_b);
+
         repeat_pwm::spawn().ok();
         update_data::spawn().ok();
-
-        (Shared { data }, Local { tx })
+        read_encoder::spawn().ok();
+        read_dht::spawn().ok();
+
+        (
+            Shared { data, cur_pos: 0.0 },
+            Local {
+                transfer: Some(transfer),
+                rx,
+                dht_rx,
+            },
+        )
     }
 
-    #[task(
-        shared = [data],
-        local = [cur_pos: f32  = 0.0]
-    )]
-    async fn update_data(c: update_data::Context) {
-        let mut data = c.shared.data;
+    // The chase animation only ever drove one digit's worth of segments;
+    // now that `PwmData` holds levels for the whole chain, keep it on the
+    // first one.
+    const ANIMATION_DIGIT: usize = 0;
+
+    // The DHT readout gets its own digit, so `update_data` rewriting
+    // `ANIMATION_DIGIT` every 15 ms doesn't overwrite it.
+    const DHT_DIGIT: usize = 1;
+
+    #[task(shared = [data, cur_pos])]
+    async fn update_data(mut c: update_data::Context) {
         loop {
-            data.lock(|data| {
-                let cur_index = super::floor(*c.local.cur_pos);
-                let fract = *c.local.cur_pos - cur_index;
+            let cur_pos = c.shared.cur_pos.lock(|cur_pos| *cur_pos);
+
+            c.shared.data.lock(|data| {
+                let cur_index = super::floor(cur_pos);
+                let fract = cur_pos - cur_index;
 
                 let cur_index = cur_index as usize;
                 let prev_index = (cur_index + 8 - 1) % 8;
                 let next_index = (cur_index + 8 + 1) % 8;
 
-                data.pwm_levels[prev_index] = 0;
-                data.pwm_levels[cur_index] = (255. * (1.0 - fract)) as u32;
-                data.pwm_levels[next_index] = (255. * (fract - 0.4) * 1.667) as u32;
+                let base = ANIMATION_DIGIT * 8;
+                data.pwm_levels[base + prev_index] = 0;
+                data.pwm_levels[base + cur_index] = (255. * (1.0 - fract)) as u32;
+                data.pwm_levels[base + next_index] = (255. * (fract - 0.4) * 1.667) as u32;
 
                 data.reflect();
             });
 
-            *c.local.cur_pos = (*c.local.cur_pos + 0.03) % 8.0;
-
             Mono::delay(15.millis()).await;
         }
     }
 
+    // Reads the accumulated edge count pushed by the quadrature decoder,
+    // divides it down to clicks, and turns that into the sweep position `update_data` interpolates
+    // around, reusing the same `floor`-based interpolation as before.
+    #[task(
+        shared = [cur_pos],
+        local = [rx],
+    )]
+    async fn read_encoder(mut c: read_encoder::Context) {
+        loop {
+            if let Some(count) = c.local.rx.read() {
+                let clicks = (count as i32) / ENCODER_EDGES_PER_CLICK;
+
+                c.shared.cur_pos.lock(|cur_pos| {
+                    *cur_pos = clicks as f32 % 8.0;
+                    if *cur_pos < 0.0 {
+                        *cur_pos += 8.0;
+                    }
+                });
+            }
+
+            Mono::delay(1.millis()).await;
+        }
+    }
+
+    // Reads one DHT22/DHT11 frame, verifies its checksum, and shows the
+    // ones digit of the temperature on `DHT_DIGIT` via `show_digit`. The
+    // temperature is decoded in the DHT22 format (tenths of a degree, sign
+    // in the top bit); a DHT11's integer and decimal bytes are not
+    // converted, so for a DHT11 the digit shown is not its temperature's.
     #[task(
         shared = [data],
-        local = [tx, step: u8 = 0],
+        local = [dht_rx],
+    )]
+    async fn read_dht(mut c: read_dht::Context) {
+        loop {
+            // Two words come out of the RX FIFO per frame: the 32
+            // humidity+temperature bits, then the trailing 8 checksum bits.
+            let Some(data_word) = c.local.dht_rx.read() else {
+                Mono::delay(1.millis()).await;
+                continue;
+            };
+            // The checksum word arrives ~1 ms after the data word; wait for
+            // it rather than dropping the data word and losing the pairing.
+            let checksum_word = loop {
+                if let Some(word) = c.local.dht_rx.read() {
+                    break word;
+                }
+                Mono::delay(1.millis()).await;
+            };
+
+            let bytes = data_word.to_be_bytes();
+            // bytes[0..=1] is humidity, available for a future readout
+            let temperature_raw = u16::from_be_bytes([bytes[2], bytes[3]]);
+            let checksum = (checksum_word & 0xff) as u8;
+
+            let expected_checksum = bytes[0]
+                .wrapping_add(bytes[1])
+                .wrapping_add(bytes[2])
+                .wrapping_add(bytes[3]);
+
+            if checksum == expected_checksum {
+                let temperature_tenths = temperature_raw & 0x7fff;
+                let ones_digit = ((temperature_tenths / 10) % 10) as u8;
+
+                c.shared.data.lock(|data| {
+                    data.show_digit(DHT_DIGIT, ones_digit, 200, false);
+                });
+            }
+
+            Mono::delay(DHT_SAMPLE_INTERVAL_MS.millis()).await;
+        }
+    }
+
+    // Rebuilds and hands off the step table once per frame instead of
+    // writing one PWM step at a time: the SM paces the waveform itself, so
+    // this task only wakes up when a buffer needs refilling, not once per
+    // step (~9 CPU wakeups per frame down to zero).
+    #[task(
+        shared = [data],
+        local = [transfer],
     )]
     async fn repeat_pwm(c: repeat_pwm::Context) {
         let mut data = c.shared.data;
-        let tx = c.local.tx;
 
         loop {
-            let steps = data.lock(|data| data.pwm_steps);
-            for step in steps {
-                tx.write(step.data << 24);
+            let (buf, next_transfer) = c.local.transfer.take().unwrap().wait();
 
-                let delay_ms = ((step.length * 100) as u64).micros();
-                Mono::delay(delay_ms).await;
-            }
-            *c.local.step = (*c.local.step + 1) % 8;
+            let packed = data.lock(|data| data.packed_steps());
+            buf.copy_from_slice(&packed);
+
+            c.local.transfer.replace(next_transfer.read_next(buf));
+
+            Mono::delay(1.millis()).await;
         }
     }
 }